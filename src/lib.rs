@@ -7,34 +7,213 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
+// `BertForMaskedLM` and the `attention_mask: Option<&Tensor>` parameter of
+// `BertModel::forward`/`BertForMaskedLM::forward` used below were added to
+// candle-transformers together; pin a candle-transformers version that
+// includes both (see the crate's CHANGELOG) or `forward` calls below won't
+// type-check.
 use candle_transformers::models::bert::{BertModel, Config, HiddenAct, DTYPE};
+use candle_transformers::models::bert::BertForMaskedLM;
 
 use anyhow::{Error as E, Result};
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
-use hf_hub::{api::sync::Api, Repo, RepoType};
+use ext_php_rs::exception::PhpException;
+use hf_hub::{api::sync::Api, Cache, Repo, RepoType};
 use serde_json::Value;
-use tokenizers::Tokenizer;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+/// Resolve a user-supplied device string (`"cpu"`, `"cuda"`/`"cuda:N"`,
+/// `"metal"`/`"metal:N"`) into a `candle_core::Device`, falling back to CPU
+/// when unset.
+fn parse_device(device: Option<String>) -> Result<Device> {
+    let device = device.unwrap_or_else(|| "cpu".to_string());
+    let (kind, ordinal) = match device.split_once(':') {
+        Some((kind, ordinal)) => (kind, ordinal.parse::<usize>().unwrap_or(0)),
+        None => (device.as_str(), 0),
+    };
+    match kind {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => {
+            #[cfg(feature = "cuda")]
+            {
+                Ok(Device::new_cuda(ordinal)?)
+            }
+            #[cfg(not(feature = "cuda"))]
+            {
+                Err(E::msg("CUDA device requested but this build was compiled without the `cuda` feature"))
+            }
+        }
+        "metal" => {
+            #[cfg(feature = "metal")]
+            {
+                Ok(Device::new_metal(ordinal)?)
+            }
+            #[cfg(not(feature = "metal"))]
+            {
+                Err(E::msg("Metal device requested but this build was compiled without the `metal` feature"))
+            }
+        }
+        other => Err(E::msg(format!("Unknown device '{}', expected 'cpu', 'cuda[:N]' or 'metal[:N]'", other))),
+    }
+}
+
+/// Pooling strategy used to collapse per-token hidden states into a single
+/// sentence embedding. Different sentence-transformer checkpoints are
+/// trained against different heads, so this must match the model in use.
+#[derive(Clone, Copy)]
+enum Pooling {
+    /// Mean over all real (non-padding) token positions, the historical default
+    Mean,
+    /// The hidden state of the first (`[CLS]`) token
+    Cls,
+    /// Elementwise max over all real (non-padding) token positions
+    Max,
+}
+
+fn parse_pooling(pooling: Option<String>) -> Result<Pooling> {
+    match pooling.as_deref() {
+        None | Some("mean") => Ok(Pooling::Mean),
+        Some("cls") => Ok(Pooling::Cls),
+        Some("max") => Ok(Pooling::Max),
+        Some(other) => Err(E::msg(format!("Unknown pooling mode '{}', expected 'mean', 'cls' or 'max'", other))),
+    }
+}
+
+/// Pool a `[batch, seq_len, hidden_size]` tensor down to `[batch, hidden_size]`,
+/// honoring `attention_mask` (`[batch, seq_len]`, 1 for real tokens, 0 for
+/// padding) so that padded positions don't skew `mean`/`max` pooling. Pass an
+/// all-ones mask when the batch has no padding (e.g. a single unpadded chunk).
+fn pool_embeddings(embeddings: &Tensor, attention_mask: &Tensor, pooling: Pooling) -> Result<Tensor> {
+    let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+    match pooling {
+        Pooling::Mean => {
+            let summed = embeddings.broadcast_mul(&mask)?.sum(1)?;
+            let counts = mask.sum(1)?;
+            Ok(summed.broadcast_div(&counts)?)
+        }
+        Pooling::Cls => Ok(embeddings.narrow(1, 0, 1)?.squeeze(1)?),
+        Pooling::Max => {
+            // Push padded positions far below any real activation so they never win the max
+            let penalty = mask.affine(1e9, -1e9)?;
+            Ok(embeddings.broadcast_add(&penalty)?.max(1)?)
+        }
+    }
+}
+
+/// Where to load model files (`config.json`, `tokenizer.json`, weights) from.
+enum ModelSource {
+    /// Read directly from a local directory on disk, e.g. for air-gapped deployments
+    LocalPath(String),
+    /// Resolve from the HuggingFace Hub, optionally restricted to what's already cached locally
+    Hub {
+        model_id: String,
+        revision: String,
+        offline: bool,
+    },
+}
+
+/// Resolve `config.json`, `tokenizer.json` and the model weights file for a
+/// `ModelSource`, without touching the network when reading from a local
+/// path or in offline mode.
+fn resolve_model_files(source: &ModelSource, use_pth: bool) -> Result<(PathBuf, PathBuf, PathBuf)> {
+    let weights_name = if use_pth { "pytorch_model.bin" } else { "model.safetensors" };
+    match source {
+        ModelSource::LocalPath(dir) => {
+            let dir = PathBuf::from(dir);
+            let config = dir.join("config.json");
+            let tokenizer = dir.join("tokenizer.json");
+            let weights = dir.join(weights_name);
+            for file in [&config, &tokenizer, &weights] {
+                if !file.exists() {
+                    return Err(E::msg(format!("Model file not found: {}", file.display())));
+                }
+            }
+            Ok((config, tokenizer, weights))
+        }
+        ModelSource::Hub {
+            model_id,
+            revision,
+            offline,
+        } => {
+            let repo = Repo::with_revision(model_id.clone(), RepoType::Model, revision.clone());
+            if *offline {
+                let cache = Cache::default().repo(repo);
+                let config = cache
+                    .get("config.json")
+                    .ok_or_else(|| E::msg("config.json not found in local hf-hub cache (offline mode)"))?;
+                let tokenizer = cache
+                    .get("tokenizer.json")
+                    .ok_or_else(|| E::msg("tokenizer.json not found in local hf-hub cache (offline mode)"))?;
+                let weights = cache
+                    .get(weights_name)
+                    .ok_or_else(|| E::msg(format!("{} not found in local hf-hub cache (offline mode)", weights_name)))?;
+                Ok((config, tokenizer, weights))
+            } else {
+                let api = Api::new()?;
+                let api = api.repo(repo);
+                let config = api.get("config.json")?;
+                let tokenizer = api.get("tokenizer.json")?;
+                let weights = api.get(weights_name)?;
+                Ok((config, tokenizer, weights))
+            }
+        }
+    }
+}
+
+/// How long input texts are split into model-sized chunks before pooling.
+#[derive(Clone, Copy)]
+enum ChunkingMode {
+    /// Split at fixed `max_seq_len`/stride offsets (the historical default).
+    /// Inserting a token near the start shifts every downstream boundary.
+    Fixed,
+    /// Content-defined chunking: cut boundaries are determined by a rolling
+    /// hash over the token ids, so unchanged regions of a document produce
+    /// identical chunks across edits.
+    ContentDefined {
+        min_size: usize,
+        max_size: usize,
+        target_size: usize,
+        overlap: usize,
+    },
+}
+
+fn parse_chunking(
+    chunking: Option<String>,
+    max_input_len: usize,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    target_size: Option<usize>,
+    overlap: Option<usize>,
+) -> Result<ChunkingMode> {
+    match chunking.as_deref() {
+        None | Some("fixed") => Ok(ChunkingMode::Fixed),
+        Some("cdc") => {
+            // Clamp to [1, max_input_len]: a chunk longer than the model window would
+            // blow up `forward`, and a max_size of 0 would stall the boundary search forever.
+            let max_size = max_size.unwrap_or(max_input_len).clamp(1, max_input_len);
+            let target_size = target_size.unwrap_or(max_size / 2).clamp(1, max_size);
+            let min_size = min_size.unwrap_or(target_size / 2).min(max_size);
+            let overlap = overlap.unwrap_or(max_input_len / 10);
+            Ok(ChunkingMode::ContentDefined {
+                min_size,
+                max_size,
+                target_size,
+                overlap,
+            })
+        }
+        Some(other) => Err(E::msg(format!("Unknown chunking mode '{}', expected 'fixed' or 'cdc'", other))),
+    }
+}
 
 fn build_model_and_tokenizer(
-    model_id: String,
-    revision: String,
+    source: ModelSource,
     use_pth: bool,
+    device: Device,
 ) -> Result<(BertModel, Tokenizer, usize, usize)> {
-    let device = Device::Cpu;
-    let repo = Repo::with_revision(model_id, RepoType::Model, revision);
-    let (config_filename, tokenizer_filename, weights_filename) = {
-        let api = Api::new()?;
-        let api = api.repo(repo);
-        let config = api.get("config.json")?;
-        let tokenizer = api.get("tokenizer.json")?;
-        let weights = if use_pth {
-            api.get("pytorch_model.bin")?
-        } else {
-            api.get("model.safetensors")?
-        };
-        (config, tokenizer, weights)
-    };
+    let (config_filename, tokenizer_filename, weights_filename) = resolve_model_files(&source, use_pth)?;
     let config = std::fs::read_to_string(config_filename)?;
     let max_input_len = get_max_input_length(&config)?;
     let hidden_size = get_hidden_size(&config)?;
@@ -51,6 +230,32 @@ fn build_model_and_tokenizer(
     Ok((model, tokenizer, max_input_len, hidden_size))
 }
 
+/// Same as `build_model_and_tokenizer`, but loads the BERT masked-LM head
+/// (`BertForMaskedLM`) instead of the plain encoder, for SPLADE-style sparse
+/// lexical expansion. Always loads onto the CPU, and returns the vocabulary
+/// size in place of the hidden size.
+fn build_masked_lm_and_tokenizer(
+    source: ModelSource,
+    use_pth: bool,
+) -> Result<(BertForMaskedLM, Tokenizer, usize, usize)> {
+    let device = Device::Cpu;
+    let (config_filename, tokenizer_filename, weights_filename) = resolve_model_files(&source, use_pth)?;
+    let config = std::fs::read_to_string(config_filename)?;
+    let max_input_len = get_max_input_length(&config)?;
+    let vocab_size = get_vocab_size(&config)?;
+    let mut config: Config = serde_json::from_str(&config)?;
+    let tokenizer: Tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+
+    let vb = if use_pth {
+        VarBuilder::from_pth(&weights_filename, DTYPE, &device)?
+    } else {
+        unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? }
+    };
+    config.hidden_act = HiddenAct::GeluApproximate;
+    let model = BertForMaskedLM::load(vb, &config)?;
+    Ok((model, tokenizer, max_input_len, vocab_size))
+}
+
 /// Get maximum input length for sequence for the current model
 fn get_max_input_length(contents: &str) -> Result<usize> {
     let config: Value = serde_json::from_str(&contents)?;
@@ -68,12 +273,25 @@ fn get_hidden_size(contents: &str) -> Result<usize> {
     Ok(max_length as usize)
 }
 
+fn get_vocab_size(contents: &str) -> Result<usize> {
+    let config: Value = serde_json::from_str(&contents)?;
+    let vocab_size = config["vocab_size"]
+        .as_u64()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Vocab size not found"))?;
+    Ok(vocab_size as usize)
+}
+
 #[php_class(name = "Manticore\\Ext\\Model")]
 struct Model {
     model: BertModel,
     tokenizer: Tokenizer,
     max_input_len: usize,
     hidden_size: usize,
+    device: Device,
+    pooling: Pooling,
+    normalize: bool,
+    first_chunk_weight: f32,
+    chunking: ChunkingMode,
 }
 
 #[php_impl(rename_methods = "camelCase")]
@@ -83,18 +301,71 @@ impl Model {
     /// @param string $model_id name of the model to use from the huggingface.co
     /// @param ?string $revision The revision of the mode to use, default is string
     /// @param bool $use_pth If we use pytorch model or safetensors
+    /// @param ?string $device Compute device to run the model on: "cpu" (default), "cuda"/"cuda:N" or "metal"/"metal:N"
+    /// @param ?string $pooling Pooling strategy: "mean" (default), "cls" or "max"
+    /// @param ?bool $normalize Whether to L2-normalize the resulting embedding, default true
+    /// @param ?float $first_chunk_weight Weight given to the first chunk when averaging chunk embeddings, default 1.2. Use 1.0 for uniform weighting
+    /// @param ?string $local_path Load the model from this local directory instead of the HuggingFace Hub (for air-gapped deployments)
+    /// @param ?bool $offline Resolve the model from the local hf-hub cache only, without making any network calls
+    /// @param ?string $chunking Chunking strategy: "fixed" (default; only inputs longer than one model window are split) or "cdc" (content-defined; inputs shorter than one model window can also be split)
+    /// @param ?int $chunk_min_size Minimum chunk size in tokens before a content-defined boundary is considered (cdc only)
+    /// @param ?int $chunk_max_size Maximum chunk size in tokens, forcing a cut if reached (cdc only); defaults to the model's max input length
+    /// @param ?int $chunk_target_size Average chunk size in tokens the rolling hash aims for (cdc only)
+    /// @param ?int $chunk_overlap Number of tokens of overlap kept between consecutive chunks for context continuity (cdc only; "fixed" always overlaps by a tenth of the max input length)
     /// @return self Instance of created class
-    pub fn create(model_id: String, revision: Option<String>, use_pth: Option<bool>) -> Self {
+    pub fn create(
+        model_id: String,
+        revision: Option<String>,
+        use_pth: Option<bool>,
+        device: Option<String>,
+        pooling: Option<String>,
+        normalize: Option<bool>,
+        first_chunk_weight: Option<f32>,
+        local_path: Option<String>,
+        offline: Option<bool>,
+        chunking: Option<String>,
+        chunk_min_size: Option<usize>,
+        chunk_max_size: Option<usize>,
+        chunk_target_size: Option<usize>,
+        chunk_overlap: Option<usize>,
+    ) -> Result<Self, PhpException> {
         let revision = revision.unwrap_or("main".to_string());
         let use_pth = use_pth.unwrap_or(false);
+        let source = match local_path {
+            Some(path) => ModelSource::LocalPath(path),
+            None => ModelSource::Hub {
+                model_id,
+                revision,
+                offline: offline.unwrap_or(false),
+            },
+        };
+        let device = parse_device(device).map_err(|e| PhpException::default(e.to_string()))?;
+        let pooling = parse_pooling(pooling).map_err(|e| PhpException::default(e.to_string()))?;
+        let normalize = normalize.unwrap_or(true);
+        let first_chunk_weight = first_chunk_weight.unwrap_or(1.2);
         let (model, tokenizer, max_input_len, hidden_size) =
-            build_model_and_tokenizer(model_id, revision, use_pth).unwrap();
-        Model {
+            build_model_and_tokenizer(source, use_pth, device.clone())
+                .map_err(|e| PhpException::default(e.to_string()))?;
+        let chunking = parse_chunking(
+            chunking,
+            max_input_len,
+            chunk_min_size,
+            chunk_max_size,
+            chunk_target_size,
+            chunk_overlap,
+        )
+        .map_err(|e| PhpException::default(e.to_string()))?;
+        Ok(Model {
             model,
             tokenizer,
             max_input_len,
             hidden_size,
-        }
+            device,
+            pooling,
+            normalize,
+            first_chunk_weight,
+            chunking,
+        })
     }
 
     /// Get maximum input len in tokens allowed for this model
@@ -113,7 +384,7 @@ impl Model {
     /// @param string $text Text to convert into the token and return array of it
     /// @return array<string>
     pub fn predict(&mut self, text: String) -> Vec<f32> {
-        let device = &self.model.device;
+        let device = &self.device;
         let tokenizer = self
             .tokenizer
             .with_padding(None)
@@ -127,26 +398,220 @@ impl Model {
             .get_ids()
             .to_vec();
 
-        let chunks = chunk_input_tokens(&tokens, self.max_input_len, (self.max_input_len / 10) as usize);
+        let chunks = match self.chunking {
+            ChunkingMode::Fixed => chunk_input_tokens(&tokens, self.max_input_len, (self.max_input_len / 10) as usize),
+            ChunkingMode::ContentDefined {
+                min_size,
+                max_size,
+                target_size,
+                overlap,
+            } => chunk_input_tokens_cdc(&tokens, min_size, max_size, target_size, overlap),
+        };
         let mut results: Vec<Vec<f32>> = Vec::new();
         for chunk in &chunks {
             let token_ids = Tensor::new(&chunk[..], device).unwrap().unsqueeze(0).unwrap();
             let token_type_ids = token_ids.zeros_like().unwrap();
-            let embeddings = self.model.forward(&token_ids, &token_type_ids).unwrap();
+            let attention_mask = token_ids.ones_like().unwrap();
+            let embeddings = self
+                .model
+                .forward(&token_ids, &token_type_ids, Some(&attention_mask))
+                .unwrap();
 
-            // Apply some avg-pooling by taking the mean embedding value for all tokens (including padding)
-            let (n_sentences, n_tokens, _hidden_size) = embeddings.dims3().unwrap();
-            let embeddings = (embeddings.sum(1).unwrap() / (n_tokens as f64)).unwrap();
+            let embeddings = pool_embeddings(&embeddings, &attention_mask, self.pooling).unwrap();
+            let n_sentences = embeddings.dims2().unwrap().0;
 
             for j in 0..n_sentences {
                 let e_j = embeddings.get(j).unwrap();
                 let mut emb: Vec<f32> = e_j.to_vec1().unwrap();
-                normalize(&mut emb);
+                if self.normalize {
+                    normalize(&mut emb);
+                }
                 results.push(emb);
                 break;
             }
         }
-        get_mean_vector(&results)
+        get_mean_vector(&results, self.first_chunk_weight)
+    }
+
+    /// Encode and embed a batch of texts in a single forward pass, instead of
+    /// looping `predict` over each text one at a time. Inputs are padded to
+    /// the longest sequence in the batch and stacked into one `[batch,
+    /// seq_len]` tensor, which amortizes the model forward pass across the
+    /// whole batch.
+    /// @param array<string> $texts Texts to convert into embeddings
+    /// @return array<array<float>>
+    pub fn predict_batch(&mut self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, PhpException> {
+        let device = &self.device;
+        let padding = PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        };
+        let truncation = TruncationParams {
+            max_length: self.max_input_len,
+            ..Default::default()
+        };
+        let tokenizer = self
+            .tokenizer
+            .with_padding(Some(padding))
+            .with_truncation(Some(truncation))
+            .map_err(|e| PhpException::default(e.to_string()))?;
+        let encodings = tokenizer
+            .encode_batch(texts, true)
+            .map_err(|e| PhpException::default(e.to_string()))?;
+        let token_ids: Vec<Vec<u32>> = encodings.iter().map(|e| e.get_ids().to_vec()).collect();
+        let attention_mask: Vec<Vec<u32>> = encodings.iter().map(|e| e.get_attention_mask().to_vec()).collect();
+        let token_ids = Tensor::new(token_ids, device).map_err(|e| PhpException::default(e.to_string()))?;
+        let attention_mask =
+            Tensor::new(attention_mask, device).map_err(|e| PhpException::default(e.to_string()))?;
+        let token_type_ids = token_ids.zeros_like().map_err(|e| PhpException::default(e.to_string()))?;
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))
+            .map_err(|e| PhpException::default(e.to_string()))?;
+
+        let embeddings = pool_embeddings(&embeddings, &attention_mask, self.pooling)
+            .map_err(|e| PhpException::default(e.to_string()))?;
+        let n_sentences = embeddings.dims2().map_err(|e| PhpException::default(e.to_string()))?.0;
+
+        let mut results: Vec<Vec<f32>> = Vec::new();
+        for j in 0..n_sentences {
+            let e_j = embeddings.get(j).map_err(|e| PhpException::default(e.to_string()))?;
+            let mut emb: Vec<f32> = e_j.to_vec1().map_err(|e| PhpException::default(e.to_string()))?;
+            if self.normalize {
+                normalize(&mut emb);
+            }
+            results.push(emb);
+        }
+        Ok(results)
+    }
+}
+
+#[php_class(name = "Manticore\\Ext\\SparseModel")]
+struct SparseModel {
+    model: BertForMaskedLM,
+    tokenizer: Tokenizer,
+    max_input_len: usize,
+    vocab_size: usize,
+}
+
+#[php_impl(rename_methods = "camelCase")]
+impl SparseModel {
+    /// Static method to instantiate the SparseModel
+    #[php_static_method]
+    /// @param string $model_id name of the model to use from the huggingface.co
+    /// @param ?string $revision The revision of the mode to use, default is "main"
+    /// @param bool $use_pth If we use pytorch model or safetensors
+    /// @param ?string $local_path Load the model from this local directory instead of the HuggingFace Hub (for air-gapped deployments)
+    /// @param ?bool $offline Resolve the model from the local hf-hub cache only, without making any network calls
+    /// @return self Instance of created class
+    pub fn create(
+        model_id: String,
+        revision: Option<String>,
+        use_pth: Option<bool>,
+        local_path: Option<String>,
+        offline: Option<bool>,
+    ) -> Result<Self, PhpException> {
+        let revision = revision.unwrap_or("main".to_string());
+        let use_pth = use_pth.unwrap_or(false);
+        let source = match local_path {
+            Some(path) => ModelSource::LocalPath(path),
+            None => ModelSource::Hub {
+                model_id,
+                revision,
+                offline: offline.unwrap_or(false),
+            },
+        };
+        let (model, tokenizer, max_input_len, vocab_size) =
+            build_masked_lm_and_tokenizer(source, use_pth).map_err(|e| PhpException::default(e.to_string()))?;
+        Ok(SparseModel {
+            model,
+            tokenizer,
+            max_input_len,
+            vocab_size,
+        })
+    }
+
+    /// Get maximum input len in tokens allowed for this model
+    /// @return int
+    #[php]
+    pub fn get_max_input_len(&mut self) -> usize {
+        self.max_input_len
+    }
+
+    /// Get the vocabulary size of the masked-LM head, i.e. the dimension
+    /// of the sparse vector produced by `predict`
+    /// @return int
+    pub fn get_hidde_size(&mut self) -> usize {
+        self.vocab_size
+    }
+
+    /// Run SPLADE-style sparse lexical expansion: `log(1 + relu(logits))` is
+    /// max-pooled over the sequence dimension, and only the nonzero term
+    /// weights are returned, keyed by the token's string form from the
+    /// tokenizer vocabulary.
+    /// @param string $text Text to convert into a sparse bag of weighted terms
+    /// @return array<string, float>
+    pub fn predict(&mut self, text: String) -> Result<HashMap<String, f32>, PhpException> {
+        let device = &self.model.bert.device;
+        let tokenizer = self
+            .tokenizer
+            .with_padding(None)
+            .with_truncation(None)
+            .map_err(|e| PhpException::default(e.to_string()))?;
+        let tokens = tokenizer
+            .encode(text.clone(), true)
+            .map_err(|e| PhpException::default(e.to_string()))?
+            .get_ids()
+            .to_vec();
+
+        let chunks = chunk_input_tokens(&tokens, self.max_input_len, (self.max_input_len / 10) as usize);
+        let mut sparse_vector = vec![0f32; self.vocab_size];
+        for chunk in &chunks {
+            let token_ids = Tensor::new(&chunk[..], device)
+                .and_then(|t| t.unsqueeze(0))
+                .map_err(|e| PhpException::default(e.to_string()))?;
+            let token_type_ids = token_ids.zeros_like().map_err(|e| PhpException::default(e.to_string()))?;
+            let attention_mask = token_ids.ones_like().map_err(|e| PhpException::default(e.to_string()))?;
+            let logits = self
+                .model
+                .forward(&token_ids, &token_type_ids, Some(&attention_mask))
+                .map_err(|e| PhpException::default(e.to_string()))?;
+
+            // SPLADE activation: log(1 + relu(logits))
+            let activated = logits
+                .relu()
+                .and_then(|t| t.affine(1.0, 1.0))
+                .and_then(|t| t.log())
+                .map_err(|e| PhpException::default(e.to_string()))?;
+            // Max-pool over the sequence dimension to collapse to [vocab_size]
+            let (_n_sentences, _n_tokens, vocab_size) =
+                activated.dims3().map_err(|e| PhpException::default(e.to_string()))?;
+            let pooled = activated.max(1).map_err(|e| PhpException::default(e.to_string()))?;
+            let weights: Vec<f32> = pooled
+                .get(0)
+                .and_then(|t| t.to_vec1())
+                .map_err(|e| PhpException::default(e.to_string()))?;
+            for (id, weight) in weights.into_iter().enumerate().take(vocab_size) {
+                if weight > sparse_vector[id] {
+                    sparse_vector[id] = weight;
+                }
+            }
+        }
+
+        let vocab = tokenizer.get_vocab(true);
+        let id_to_token: HashMap<u32, &String> = vocab.iter().map(|(token, id)| (*id, token)).collect();
+        let mut result = HashMap::new();
+        for (id, weight) in sparse_vector.into_iter().enumerate() {
+            if weight <= 0.0 {
+                continue;
+            }
+            let key = id_to_token
+                .get(&(id as u32))
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| id.to_string());
+            result.insert(key, weight);
+        }
+        Ok(result)
     }
 }
 
@@ -175,7 +640,117 @@ fn chunk_input_tokens(tokens: &[u32], max_seq_len: usize, stride: usize) -> Vec<
     chunks
 }
 
-fn get_mean_vector(results: &Vec<Vec<f32>>) -> Vec<f32> {
+/// Fixed 256-entry random table used by the gear/Rabin-style rolling hash in
+/// `chunk_input_tokens_cdc`. Indexed by `token_id & 0xFF`.
+const GEAR: [u64; 256] = [
+    0xDAEB8EBD244A330C, 0x685BD8519D0023DB, 0x959EF8713231C2CA, 0xD1EA2FA4DD9AF44C,
+    0xA402CBA46B82BDDD, 0x4F7580CD7B17A39E, 0xC8B045B99D6FB286, 0xCECA0CA0C351E0A7,
+    0x38987F53584DF3C8, 0xBB74476EE0B6E30F, 0x9474C83868219521, 0xA309F5FBA2117B34,
+    0xF901131499F29AAD, 0x6568525F65BE34AE, 0xE61C980E7426B628, 0xF330A10B9EFE9904,
+    0x39381640553D574D, 0x0E6C783BD0D3AAC1, 0x992877185800058A, 0xE2B445A3CB88BB30,
+    0x42381838BF9D61AF, 0x475B2AF9C112B40F, 0x9D73761A2479742F, 0xA5869770CC27FDBA,
+    0x0CE9FCBA3E066D3A, 0x40254DFC5F952DDA, 0xBE90976FA0B88C66, 0xC764B0449A0FCAE9,
+    0x0D50E066AA379226, 0x1C89878831D2174B, 0x5192725A6354374E, 0xCCD9E6665A015063,
+    0xC3E6ACEE47500CF7, 0x3CCBCD51AD9BEC8B, 0xEAA54832ABC0D042, 0x1B447AE964C1C89A,
+    0x0AC1595AC5C3C0B6, 0x3BE57C826F738D74, 0xB2285AACD34440FF, 0xD379BB36D3F73E92,
+    0x21BCA2C338EC4530, 0x81C189F6FA0B9FBC, 0xDC931E17350C1918, 0xD73F0B44720F86BF,
+    0x02265866D923DD6B, 0x4304B6980C596849, 0x930C5483D2E3D818, 0x7508BB12D38AE9A8,
+    0x483B9E4A3553D717, 0x91042DA51A43F6A6, 0xCD388E7C56F288BF, 0x657DB3A23FB1F544,
+    0xC37F8CBA1BB658FF, 0x3F80E82E94985DCD, 0x50024265FE7EBB2F, 0x58159F4FDC1D8BD5,
+    0xA8EE121047B5EE36, 0x5AAD8D0F2198D2C5, 0x0FEC8FCD73F64B4D, 0xBD2F206F339B8FF7,
+    0x4FBCF455E30E7D5C, 0x7AFC1109EFE0B1D8, 0x34849218AA1BC1D2, 0xE05A2AF0326A51AA,
+    0xB8031A57A91AD512, 0xF7F55DA8F50A5343, 0xF67A6E8C8421B13F, 0x6483F2A7F3D0FFEC,
+    0x06FBE1C1A9BDAA56, 0xE6C83895A9B2B597, 0x297D4A92F1B5DDD6, 0xA5AD1AE892A2E0FD,
+    0x70378245866AB36D, 0xD8570898EEB3162C, 0xA38B7CA71B8B7497, 0xA8F84AD0345BE4AC,
+    0x3CBF878918DA15E5, 0x32666CDF5FEC35DA, 0x1A7E5607CB4060A6, 0x2564CACC359A9AF7,
+    0x44830BD8F0A0F070, 0x5E10BE8057009C16, 0xD43D3308E8C478BF, 0x89B9EBF0CB6988C5,
+    0xB162E14BDE10F91E, 0x066D2240225EA8F8, 0x34C981A521A40679, 0x5E62EA28843EFA3F,
+    0x4AB821F3D99B0602, 0x185876D84B1A3F02, 0x3FF870589E0C737E, 0xE4B6325442D17832,
+    0x83F5DADDC07C3F0C, 0x21B413AEE612619E, 0x52F1EA9A03E41CCD, 0x8FD94855822B982B,
+    0x928022824B5EEDAA, 0xF6732C9446496F2E, 0x81BBE422CD847349, 0x9088E2EC86BC7FD6,
+    0x93A935FA56BA1C5F, 0x79B9A33F54417134, 0x89D9664ECCA98EA6, 0xEA6C8B82675A008D,
+    0xA88B2259755AC015, 0xF0459DEFEC2456CC, 0x6F6D0DC2A3B5D1C6, 0xD6D9C4B5EDDB5474,
+    0x38D4631445250313, 0xC6ED3137B39E9862, 0x860CD4B3C9FD4247, 0x9A0EB79035416FF3,
+    0x388008A942804C7E, 0x29E9133A40E25AF2, 0xC5F1742FD3E20074, 0xA36829D9B12CF9E1,
+    0xF8F5FEE8DD9834DB, 0xB0117AF959788F60, 0xD1EB51DF61A9BBDA, 0xC3110319DC077BC9,
+    0x5838B4E6615301CA, 0xB600C09A0DC61203, 0xA0048520FDDC94B8, 0x075EC507835F3178,
+    0x9191A970F8A6528D, 0x50A059A9A0173830, 0x40130C670933A072, 0xD50591572C101563,
+    0xFFC0457BB7647DE6, 0xB2753786D818934C, 0xB4ADDD011D1FC8D5, 0xC00E3068CF1B7AD1,
+    0x1CF4DE9AE42815E4, 0x3D148B101D1A41FD, 0x0B87334C4F4154F7, 0x274F6F5AA2A3F244,
+    0xF964A3A5F9EF8EFB, 0x80442E46D1D0BC5B, 0xB5405444C921BEA0, 0x94A9E7398C47C2B4,
+    0x9137DDD5898AB67A, 0xD88B9A2C8B6B355A, 0xCF02344B3119BFF7, 0xF464FA8E415E7B61,
+    0x9E962460D77C94FC, 0x30C443571F5FB2E9, 0x6123EFA561E9C370, 0x56A314EBCCA7A4EB,
+    0x5E8B3B962635131B, 0x7465B7C987A738FC, 0x6FCEB68A5247DBF7, 0x512E181264C78E2F,
+    0x17B0DDF52CEC7B42, 0x7185606E6365F3A6, 0xE3419536DAF252E5, 0xD6FE3215867F8D71,
+    0xBB50DA01193A3A3B, 0xF5E3C1E56A1D352A, 0x9B4C08BE3A4DAE22, 0xF62F1E58EA517B4B,
+    0x391E2DDD78073598, 0x9FFEAAE3EBB016A4, 0x552A71489CC45822, 0xF134BFE06244C61D,
+    0x6FE7B9F548E38D8B, 0x6E2F654A84559B4D, 0xDBF649C2B001A9AC, 0xC1D52BD8774FF7D0,
+    0xCC72229638934F6E, 0xB898BF3668DADB6F, 0xFE1387BFCCFBB924, 0x8975C8D03D081421,
+    0x02B4302ACA1E50CE, 0x1CA2CD0DC899D0E2, 0x3B9EC4E1EDBBD3F4, 0x3CCFB8040C12DE20,
+    0x271AC7FBB361CB04, 0xAAC96673241A8FDB, 0xAD44AAE74FFE6367, 0x4DB28CDC208B12F9,
+    0x09DE29AFBBA64998, 0x6F83B226D5AD40CB, 0x67794A52A1557D9F, 0xECB75608F1CAADF8,
+    0xB860DD9731C80904, 0xB46D859406F8895E, 0xEC257A7D529F56ED, 0x7187ACF5B729D1C4,
+    0x4C8D41E544BA9AE4, 0x77F1884A101C3295, 0x39B873922047E1CB, 0xAFE2EDA84AD55956,
+    0xCF933BA3ADAE3EF2, 0x507CA6308E4061DE, 0xEE637FF0D4EFD9A3, 0xA0947C07C10ACE92,
+    0x8767CF6AB6313531, 0xB1000EA9C7A85B78, 0x7124649FBE312367, 0x34078E9C4E5ACD6D,
+    0xFBAA0B73A112FD35, 0xC16D341FE60B4C6C, 0xBC360D67C05DE8A2, 0xAD7189BF012B76D3,
+    0x457380482331D42E, 0x36AED547994CF6E6, 0x49E92033D31198CE, 0x1AA9F06D4FC1C5E2,
+    0x5BDBCE793A6A290B, 0xF63C5F3BC2B01D2E, 0xE1954AD3F7B43A0A, 0xBADB13EE86A957A6,
+    0x6AEEFABDB8419DAE, 0x0109B7CC98C3A028, 0x4AA04515A4DFCAFD, 0xF8886C180A655DEC,
+    0x9A68F670370E7F6D, 0xA9CE17CEBBA58544, 0x22BD14BBCB2D45E2, 0x4EA337D0FE4E6396,
+    0xA72A7DD42A1E2A52, 0xEE95D0154C6EC863, 0xFCBDCF15D686FDA6, 0xCDDE808BB7332D60,
+    0x87B37EA789D4A476, 0x7B916DD970D9200F, 0x6C6EB263DF472243, 0xEF21DA6CE04216D2,
+    0xC3F59D71FAE9DA84, 0xAE2D396D1FDC4F02, 0xF5F63E3F2353EE76, 0x647D4156C10AC5A4,
+    0x032D4578DBA312DE, 0x7B61C84C3C264548, 0xF89EC51442AB2EDA, 0x1A21F98905216E35,
+    0x3071DD2B6F5B9114, 0x5FBC01A82B7A9815, 0xB3BC709F71EF83A8, 0x74D605076E2C74A5,
+    0x23148DF8A4E5E749, 0x2E4A6059FA95B7CD, 0xFAF778B882E6B09D, 0x99DFB91B97792F8C,
+    0x10F743980C830116, 0x154E73572B7E79E4, 0x971E6AB2DD88161A, 0x0F23C87517408AFE,
+    0x47E091258DDFF9F7, 0xD0A542EC51C81AE9, 0xDC311B9C7129A920, 0x12732CBFB74B0B35,
+    0xC9AB25B24B450B17, 0x0021AB9602145B92, 0xF08618B551C66C1F, 0xCEA883A14ECCFC56,
+];
+
+/// Boundary mask for content-defined chunking: roughly `log2(target_size)`
+/// low bits set, so a cut is expected on average every `target_size` tokens.
+fn cdc_mask(target_size: usize) -> u64 {
+    let bits = (target_size.max(2) as f64).log2().round() as u32;
+    (1u64 << bits.min(63)) - 1
+}
+
+/// Content-defined chunking: cut boundaries are placed where a gear/Rabin-style
+/// rolling hash over the token ids satisfies `hash & mask == 0`, instead of at
+/// fixed offsets. This keeps chunk boundaries aligned to content, so edits far
+/// from a boundary don't reshuffle every downstream chunk.
+fn chunk_input_tokens_cdc(tokens: &[u32], min_size: usize, max_size: usize, target_size: usize, overlap: usize) -> Vec<Vec<u32>> {
+    let mask = cdc_mask(target_size);
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+
+    while start < tokens.len() {
+        let limit = std::cmp::min(start + max_size, tokens.len());
+        let min_end = std::cmp::min(start + min_size, tokens.len());
+        let mut hash: u64 = 0;
+        let mut end = start;
+
+        while end < limit {
+            let token_id = tokens[end];
+            hash = (hash << 1).wrapping_add(GEAR[(token_id & 0xFF) as usize]);
+            end += 1;
+            if end >= min_end && hash & mask == 0 {
+                break;
+            }
+        }
+
+        chunks.push(tokens[start..end].to_vec());
+        if end >= tokens.len() {
+            break;
+        }
+        start = if end > overlap && end - overlap > start { end - overlap } else { end };
+    }
+
+    chunks
+}
+
+fn get_mean_vector(results: &Vec<Vec<f32>>, first_chunk_weight: f32) -> Vec<f32> {
     if results.is_empty() {
         return Vec::new();
     }
@@ -186,7 +761,7 @@ fn get_mean_vector(results: &Vec<Vec<f32>>) -> Vec<f32> {
     let mut weight_sum = 0.0;
 
     for (i, row) in results.iter().enumerate() {
-        let weight = if i == 0 { 1.2 } else { 1.0 }; // Adjust the weight for the first chunk here
+        let weight = if i == 0 { first_chunk_weight } else { 1.0 };
         weight_sum += weight;
 
         for (j, val) in row.iter().enumerate() {